@@ -0,0 +1,34 @@
+use alloc::{collections::BTreeMap, string::String};
+
+/// Parsed `key=value` kernel command line handed to `kernel_main` by the
+/// bootloader (e.g. `root=pata1 log=debug noacpi`). A bare token with no
+/// `=` (a flag like `noacpi`) is recorded with an empty value so `is_set`
+/// can still report it was present.
+pub struct CommandLine {
+    options: BTreeMap<String, String>,
+}
+
+impl CommandLine {
+    pub fn parse(raw: &str) -> Self {
+        let mut options = BTreeMap::new();
+        for token in raw.split_whitespace() {
+            match token.split_once('=') {
+                Some((key, value)) => {
+                    options.insert(String::from(key), String::from(value));
+                }
+                None => {
+                    options.insert(String::from(token), String::new());
+                }
+            }
+        }
+        Self { options }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+
+    pub fn is_set(&self, key: &str) -> bool {
+        self.options.contains_key(key)
+    }
+}
@@ -0,0 +1,197 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use crate::fs::open;
+
+const CONFIG_PATH: &str = "/config";
+const CONFIG_TMP_PATH: &str = "/config.tmp";
+const COMPACT_THRESHOLD: usize = 64 * 1024;
+
+const RECORD_SET: u8 = 0;
+const RECORD_TOMBSTONE: u8 = 1;
+
+/// Durable key/value store layered on a single log file (`/config` on
+/// `dev_name` by default), used to keep boot settings (default root
+/// device, log level, ...) across reboots instead of hard-coding them in
+/// `kernel_main`.
+///
+/// Every `set`/`remove` reads the whole log, adds the new record, and
+/// rewrites the file from scratch: the `fs` layer only demonstrates `r`
+/// and `w` open modes anywhere in this tree, and an unverified `a` would
+/// risk silently not appending at all. Rewriting `/config` directly
+/// would mean a crash between truncating it and finishing the rewrite
+/// loses the whole store, so every rewrite is first written in full to
+/// `/config.tmp` and only copied over `/config` once that succeeds;
+/// `recover` replays `/config.tmp` over `/config` on the next `open` if
+/// a crash ever left the copy unfinished. `get` keeps the last record
+/// seen per key; compaction drops superseded and tombstoned entries once
+/// the log grows past `COMPACT_THRESHOLD`.
+pub struct ConfigStore {
+    dev_name: String,
+}
+
+impl ConfigStore {
+    pub fn open(dev_name: &str) -> Self {
+        let store = Self {
+            dev_name: String::from(dev_name),
+        };
+        store.recover();
+        store
+    }
+
+    /// `write_records` always finishes writing `/config.tmp` before it
+    /// ever touches `/config`, so `/config.tmp` is always at least as
+    /// complete as `/config` -- replaying it is always safe, and a no-op
+    /// once a write has gone on to finish cleanly (both files end up
+    /// holding the same bytes).
+    fn recover(&self) {
+        let Ok(mut tmp) = open(&self.dev_name, 0, CONFIG_TMP_PATH, b"r") else {
+            return;
+        };
+
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match tmp.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        if let Ok(mut file) = open(&self.dev_name, 0, CONFIG_PATH, b"w") {
+            let _ = file.write(&data);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.read_records()
+            .into_iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, value)| value)
+    }
+
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<(), ()> {
+        self.append(key, Some(value))?;
+        self.maybe_compact()
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), ()> {
+        self.append(key, None)?;
+        self.maybe_compact()
+    }
+
+    pub fn erase_all(&self) -> Result<(), ()> {
+        self.write_records(&[])
+    }
+
+    fn append(&self, key: &str, value: Option<&[u8]>) -> Result<(), ()> {
+        let mut records = self.read_records();
+        records.push((String::from(key), value.map(<[u8]>::to_vec)));
+        self.write_records(&records)
+    }
+
+    /// Writes the full record set to `/config.tmp` before copying it over
+    /// `/config`, so a crash can never catch `/config` mid truncate-then-
+    /// rewrite without leaving a complete copy of the same bytes sitting
+    /// in `/config.tmp` for `recover` to restore from.
+    fn write_records(&self, records: &[(String, Option<Vec<u8>>)]) -> Result<(), ()> {
+        let mut encoded = Vec::new();
+        for (key, value) in records {
+            encoded.extend_from_slice(&encode_record(key, value.as_deref()));
+        }
+
+        let mut tmp = open(&self.dev_name, 0, CONFIG_TMP_PATH, b"w").map_err(|_| ())?;
+        tmp.write(&encoded).map_err(|_| ())?;
+
+        let mut file = open(&self.dev_name, 0, CONFIG_PATH, b"w").map_err(|_| ())?;
+        file.write(&encoded).map_err(|_| ())
+    }
+
+    fn read_records(&self) -> Vec<(String, Option<Vec<u8>>)> {
+        let Ok(mut file) = open(&self.dev_name, 0, CONFIG_PATH, b"r") else {
+            return Vec::new();
+        };
+
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        decode_records(&data)
+    }
+
+    fn maybe_compact(&self) -> Result<(), ()> {
+        let records = self.read_records();
+        let approx_size: usize = records
+            .iter()
+            .map(|(key, value)| key.len() + value.as_ref().map_or(0, Vec::len) + 4)
+            .sum();
+        if approx_size < COMPACT_THRESHOLD {
+            return Ok(());
+        }
+
+        let mut latest = BTreeMap::new();
+        for (key, value) in records {
+            latest.insert(key, value);
+        }
+
+        let compacted: Vec<(String, Option<Vec<u8>>)> = latest
+            .into_iter()
+            .filter(|(_, value)| value.is_some())
+            .collect();
+        self.write_records(&compacted)
+    }
+}
+
+fn encode_record(key: &str, value: Option<&[u8]>) -> Vec<u8> {
+    let mut record = Vec::with_capacity(key.len() + 4 + value.map_or(0, <[u8]>::len));
+    record.push(key.len() as u8);
+    record.extend_from_slice(key.as_bytes());
+    match value {
+        Some(value) => {
+            record.push(RECORD_SET);
+            record.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            record.extend_from_slice(value);
+        }
+        None => {
+            record.push(RECORD_TOMBSTONE);
+            record.extend_from_slice(&0u16.to_le_bytes());
+        }
+    }
+    record
+}
+
+fn decode_records(data: &[u8]) -> Vec<(String, Option<Vec<u8>>)> {
+    let mut records = Vec::new();
+    let mut cursor = 0;
+    while cursor + 2 <= data.len() {
+        let key_len = data[cursor] as usize;
+        cursor += 1;
+        if cursor + key_len + 3 > data.len() {
+            break;
+        }
+        let key = String::from_utf8_lossy(&data[cursor..cursor + key_len]).into_owned();
+        cursor += key_len;
+
+        let kind = data[cursor];
+        cursor += 1;
+        let value_len = u16::from_le_bytes([data[cursor], data[cursor + 1]]) as usize;
+        cursor += 2;
+        if cursor + value_len > data.len() {
+            break;
+        }
+
+        let value = (kind == RECORD_SET).then(|| data[cursor..cursor + value_len].to_vec());
+        cursor += value_len;
+
+        records.push((key, value));
+    }
+    records
+}
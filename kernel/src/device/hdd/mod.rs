@@ -0,0 +1,63 @@
+pub mod pata;
+
+use core::mem::size_of;
+
+/// Fixed-size sector buffer shared by every mass-storage driver.
+#[derive(Clone, Copy)]
+pub struct Block<const N: usize> {
+    data: [u8; N],
+}
+
+impl<const N: usize> Block<N> {
+    pub const fn empty() -> Self {
+        Self { data: [0; N] }
+    }
+
+    pub fn get<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= N);
+        unsafe { (self.data.as_ptr().add(offset) as *const T).read_unaligned() }
+    }
+
+    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T {
+        assert!(offset + size_of::<T>() <= N);
+        unsafe { &mut *(self.data.as_mut_ptr().add(offset) as *mut T) }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.data.as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.data.as_mut_ptr()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    NotReady,
+    Timeout,
+    DeviceError,
+    DmaUnavailable,
+}
+
+/// Common interface every mass-storage driver (PATA, virtio-blk, ...) implements.
+///
+/// `read_block_dma`/`write_block_dma` default to the PIO path so a driver that
+/// hasn't wired up bus-master DMA keeps working; drivers that support DMA
+/// override them and advertise it through `dma_available`.
+pub trait BlockDevice<const N: usize> {
+    fn read_block(&self, lba: u32, buffer: &mut [Block<N>]) -> Result<(), BlockError>;
+    fn write_block(&self, lba: u32, buffer: &[Block<N>]) -> Result<(), BlockError>;
+
+    fn read_block_dma(&self, lba: u32, buffer: &mut [Block<N>]) -> Result<(), BlockError> {
+        self.read_block(lba, buffer)
+    }
+
+    fn write_block_dma(&self, lba: u32, buffer: &[Block<N>]) -> Result<(), BlockError> {
+        self.write_block(lba, buffer)
+    }
+
+    fn dma_available(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,351 @@
+use core::arch::asm;
+use core::mem::size_of;
+
+use log::{debug, warn};
+use spin::Mutex;
+
+use crate::{
+    allocator::{free, malloc},
+    device::{
+        hdd::{Block, BlockDevice, BlockError},
+        pci::{
+            search::{Base, Interface, PciSearcher, Sub},
+            PciDevice,
+        },
+    },
+};
+
+const SECTOR_SIZE: usize = 512;
+const CHANNEL_COUNT: usize = 2;
+const DRIVE_PER_CHANNEL: usize = 2;
+
+const ATA_CMD_READ_PIO: u8 = 0x20;
+const ATA_CMD_WRITE_PIO: u8 = 0x30;
+const ATA_CMD_READ_DMA: u8 = 0xC8;
+const ATA_CMD_WRITE_DMA: u8 = 0xCA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+const ATA_REG_DATA: u16 = 0x00;
+const ATA_REG_SECTOR_COUNT: u16 = 0x02;
+const ATA_REG_LBA_LOW: u16 = 0x03;
+const ATA_REG_LBA_MID: u16 = 0x04;
+const ATA_REG_LBA_HIGH: u16 = 0x05;
+const ATA_REG_DRIVE_HEAD: u16 = 0x06;
+const ATA_REG_COMMAND: u16 = 0x07;
+const ATA_REG_STATUS: u16 = 0x07;
+
+const ATA_SR_BSY: u8 = 0x80;
+const ATA_SR_DRQ: u8 = 0x08;
+const ATA_SR_ERR: u8 = 0x01;
+
+/// Bus Master IDE registers, offset from the BAR4-derived base.
+const BM_REG_COMMAND: u16 = 0x00;
+const BM_REG_STATUS: u16 = 0x02;
+const BM_REG_PRDT: u16 = 0x04;
+
+const BM_CMD_START: u8 = 0x01;
+const BM_CMD_READ: u8 = 0x08;
+const BM_STATUS_ERROR: u8 = 0x02;
+const BM_STATUS_IRQ: u8 = 0x04;
+
+/// One entry of the Physical Region Descriptor Table consumed by the
+/// bus-master controller: a physical buffer address, its byte count and an
+/// end-of-table marker in bit 15 of the high word.
+#[repr(C, packed)]
+struct PrdEntry {
+    addr: u32,
+    byte_count: u16,
+    flags: u16,
+}
+
+const PRD_EOT: u16 = 0x8000;
+
+/// A 4-byte-aligned, sub-4GiB PRDT holding a single entry, so it
+/// describes one contiguous physical region per transfer.
+///
+/// `PrdEntry` is `repr(C, packed)` (alignment 1) so a `Vec<PrdEntry>`'s
+/// backing allocation would only ever be guaranteed byte-aligned; the
+/// bus-master controller requires the table itself to start on a 4-byte
+/// boundary, so it's allocated directly through `malloc` instead.
+struct Prdt {
+    entries: *mut PrdEntry,
+}
+
+impl Prdt {
+    fn single(phys_addr: u32, byte_count: u16) -> Self {
+        let entries = malloc(size_of::<PrdEntry>(), 4).cast::<PrdEntry>();
+        unsafe {
+            *entries = PrdEntry {
+                addr: phys_addr,
+                byte_count,
+                flags: PRD_EOT,
+            };
+        }
+        Self { entries }
+    }
+
+    fn phys_addr(&self) -> u32 {
+        self.entries as u32
+    }
+}
+
+impl Drop for Prdt {
+    fn drop(&mut self) {
+        free(self.entries.cast());
+    }
+}
+
+/// Bus-master PRDT entries (and the PRDT pointer itself) carry *physical*
+/// addresses, but every buffer this driver hands the controller --
+/// sector buffers, the PRDT -- comes from `crate::allocator`, which backs
+/// the kernel heap with an identity mapping over the low 4GiB of physical
+/// memory, so a heap pointer doubles as its own physical address here.
+/// Debug-asserted rather than silently truncated, since a heap grown past
+/// 4GiB would otherwise point the controller at the wrong page.
+fn phys_addr_of(ptr: *const u8) -> u32 {
+    debug_assert!(
+        (ptr as u64) <= u32::MAX as u64,
+        "PATA DMA buffer outside the identity-mapped low 4GiB"
+    );
+    ptr as u32
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    io_base: u16,
+    control_base: u16,
+    bus_master_base: u16,
+}
+
+impl Channel {
+    fn wait_not_busy(&self) -> Result<(), BlockError> {
+        for _ in 0..100_000 {
+            if unsafe { inb(self.io_base + ATA_REG_STATUS) } & ATA_SR_BSY == 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        for _ in 0..100_000 {
+            let status = unsafe { inb(self.io_base + ATA_REG_STATUS) };
+            if status & ATA_SR_ERR != 0 {
+                return Err(BlockError::DeviceError);
+            }
+            if status & ATA_SR_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn select(&self, drive: u8, lba: u32) {
+        unsafe {
+            outb(
+                self.io_base + ATA_REG_DRIVE_HEAD,
+                0xE0 | (drive << 4) | ((lba >> 24) & 0x0F) as u8,
+            );
+            outb(self.io_base + ATA_REG_SECTOR_COUNT, 1);
+            outb(self.io_base + ATA_REG_LBA_LOW, lba as u8);
+            outb(self.io_base + ATA_REG_LBA_MID, (lba >> 8) as u8);
+            outb(self.io_base + ATA_REG_LBA_HIGH, (lba >> 16) as u8);
+        }
+    }
+
+    fn dma_available(&self) -> bool {
+        self.bus_master_base != 0
+    }
+}
+
+/// A single PATA drive, addressed by channel and drive-select bit.
+pub struct Pata {
+    channel: Channel,
+    drive: u8,
+}
+
+static CHANNELS: Mutex<[Channel; CHANNEL_COUNT]> = Mutex::new([Channel {
+    io_base: 0,
+    control_base: 0,
+    bus_master_base: 0,
+}; CHANNEL_COUNT]);
+
+/// Probe the IDE controller's legacy I/O ports and its BAR4 bus-master
+/// window, recording both so drives handed out by `get_device` can fall
+/// back to PIO when DMA isn't wired up (legacy-mode controllers, or BAR4
+/// reading as zero).
+pub fn init_pata() {
+    let bus_master_base = PciSearcher::new()
+        .base(Base::MassStorage)
+        .sub(Sub::IDE)
+        .interface(Interface::None)
+        .search()
+        .ok()
+        .and_then(|devices| devices.first())
+        .map(|dev: PciDevice| (dev.read_bar(4) & !0x3) as u16)
+        .unwrap_or(0);
+
+    if bus_master_base != 0 {
+        debug!("PATA: bus-master IDE base=0x{bus_master_base:04X}");
+    } else {
+        warn!("PATA: no bus-master BAR found, DMA transfers disabled");
+    }
+
+    let mut channels = CHANNELS.lock();
+    channels[0] = Channel {
+        io_base: 0x1F0,
+        control_base: 0x3F6,
+        bus_master_base,
+    };
+    channels[1] = Channel {
+        io_base: 0x170,
+        control_base: 0x376,
+        bus_master_base: if bus_master_base != 0 {
+            bus_master_base + 0x08
+        } else {
+            0
+        },
+    };
+}
+
+/// `index` is `channel * 2 + drive`, matching the historical PATA0..PATA3
+/// numbering used by `kernel_main`.
+pub fn get_device(index: usize) -> Result<Pata, BlockError> {
+    if index >= CHANNEL_COUNT * DRIVE_PER_CHANNEL {
+        return Err(BlockError::DeviceError);
+    }
+    let channel = CHANNELS.lock()[index / DRIVE_PER_CHANNEL];
+    let drive = (index % DRIVE_PER_CHANNEL) as u8;
+
+    channel.select(drive, 0);
+    unsafe { outb(channel.io_base + ATA_REG_COMMAND, ATA_CMD_IDENTIFY) };
+    if unsafe { inb(channel.io_base + ATA_REG_STATUS) } == 0 {
+        return Err(BlockError::DeviceError);
+    }
+
+    Ok(Pata { channel, drive })
+}
+
+impl Pata {
+    fn pio_transfer(&self, lba: u32, command: u8, read: bool, block: &mut Block<SECTOR_SIZE>) -> Result<(), BlockError> {
+        self.channel.wait_not_busy()?;
+        self.channel.select(self.drive, lba);
+        unsafe { outb(self.channel.io_base + ATA_REG_COMMAND, command) };
+        self.channel.wait_drq()?;
+
+        for word in 0..SECTOR_SIZE / 2 {
+            if read {
+                let value: u16;
+                unsafe {
+                    asm!("in ax, dx", out("ax") value, in("dx") self.channel.io_base + ATA_REG_DATA, options(nomem, nostack, preserves_flags));
+                }
+                *block.get_mut(word * 2) = value;
+            } else {
+                let value: u16 = block.get(word * 2);
+                unsafe {
+                    asm!("out dx, ax", in("dx") self.channel.io_base + ATA_REG_DATA, in("ax") value, options(nomem, nostack, preserves_flags));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dma_transfer(&self, lba: u32, command: u8, read: bool, block: &Block<SECTOR_SIZE>) -> Result<(), BlockError> {
+        if !self.channel.dma_available() {
+            return Err(BlockError::DmaUnavailable);
+        }
+        let bm_base = self.channel.bus_master_base;
+        let prdt = Prdt::single(phys_addr_of(block.as_ptr()), SECTOR_SIZE as u16);
+
+        self.channel.wait_not_busy()?;
+        unsafe {
+            outl(bm_base + BM_REG_PRDT, prdt.phys_addr());
+            outb(bm_base + BM_REG_STATUS, BM_STATUS_ERROR | BM_STATUS_IRQ);
+            outb(bm_base + BM_REG_COMMAND, if read { BM_CMD_READ } else { 0 });
+        }
+
+        self.channel.select(self.drive, lba);
+        unsafe { outb(self.channel.io_base + ATA_REG_COMMAND, command) };
+
+        unsafe {
+            let start = if read { BM_CMD_READ | BM_CMD_START } else { BM_CMD_START };
+            outb(bm_base + BM_REG_COMMAND, start);
+        }
+
+        let mut status = 0;
+        let mut completed = false;
+        for _ in 0..100_000 {
+            status = unsafe { inb(bm_base + BM_REG_STATUS) };
+            if status & BM_STATUS_IRQ != 0 {
+                completed = true;
+                break;
+            }
+        }
+
+        unsafe { outb(bm_base + BM_REG_COMMAND, 0) };
+
+        if !completed {
+            return Err(BlockError::Timeout);
+        }
+
+        if status & BM_STATUS_ERROR != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice<SECTOR_SIZE> for Pata {
+    fn read_block(&self, lba: u32, buffer: &mut [Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        for (offset, block) in buffer.iter_mut().enumerate() {
+            self.pio_transfer(lba + offset as u32, ATA_CMD_READ_PIO, true, block)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u32, buffer: &[Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        for (offset, block) in buffer.iter().enumerate() {
+            let mut scratch = *block;
+            self.pio_transfer(lba + offset as u32, ATA_CMD_WRITE_PIO, false, &mut scratch)?;
+        }
+        Ok(())
+    }
+
+    fn read_block_dma(&self, lba: u32, buffer: &mut [Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        if !self.dma_available() {
+            return self.read_block(lba, buffer);
+        }
+        for (offset, block) in buffer.iter_mut().enumerate() {
+            self.dma_transfer(lba + offset as u32, ATA_CMD_READ_DMA, true, block)?;
+        }
+        Ok(())
+    }
+
+    fn write_block_dma(&self, lba: u32, buffer: &[Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        if !self.dma_available() {
+            return self.write_block(lba, buffer);
+        }
+        for (offset, block) in buffer.iter().enumerate() {
+            self.dma_transfer(lba + offset as u32, ATA_CMD_WRITE_DMA, false, block)?;
+        }
+        Ok(())
+    }
+
+    fn dma_available(&self) -> bool {
+        self.channel.dma_available()
+    }
+}
@@ -0,0 +1,418 @@
+use core::arch::asm;
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use log::{debug, info, warn};
+use spin::Mutex;
+
+use crate::device::{
+    hdd::{Block, BlockDevice, BlockError},
+    pci::{
+        msi::{Message, Msi},
+        search::PciSearcher,
+        PciDevice,
+    },
+    virtio::{status, VENDOR_ID},
+};
+
+const SECTOR_SIZE: usize = 512;
+const PAGE_SIZE: usize = 4096;
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+unsafe fn outw(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+#[repr(C)]
+struct VringDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The available ring's fixed header; its `ring: [u16; size]` body
+/// follows immediately in memory; `size` is only known at probe time (the
+/// device's negotiated `REG_QUEUE_SIZE`), so it can't be a Rust array
+/// field.
+#[repr(C)]
+struct VringAvail {
+    flags: u16,
+    idx: u16,
+}
+
+#[repr(C)]
+struct VringUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Same deal as `VringAvail`: `ring: [VringUsedElem; size]` follows this
+/// header in memory.
+#[repr(C)]
+struct VringUsed {
+    flags: u16,
+    idx: u16,
+}
+
+/// The standard three-part split virtqueue ring: a descriptor table the
+/// driver fills in, an available ring the driver publishes new heads to,
+/// and a used ring the device publishes completions to.
+///
+/// All three live in a single, page-aligned, physically-contiguous
+/// allocation (`mem`) laid out per the legacy virtio-pci layout -- desc
+/// table, then the available ring, then padding up to the next page,
+/// then the used ring -- since `REG_QUEUE_ADDRESS` only gives the device
+/// one guest-physical page frame number to derive every ring's address
+/// from.
+struct VirtQueue {
+    mem: *mut u8,
+    size: u16,
+    desc: *mut VringDesc,
+    avail: *mut VringAvail,
+    used: *mut VringUsed,
+    free_head: u16,
+    last_used_idx: u16,
+    /// Heads `drain_used` has seen complete but nothing has claimed yet.
+    /// Both `request`'s spin loop and `on_interrupt` call `drain_used`,
+    /// so whichever of them gets there first doesn't drain a completion
+    /// out from under the other -- it just ends up recorded here for
+    /// whoever is actually waiting on that head.
+    completed: BTreeSet<u16>,
+}
+
+impl VirtQueue {
+    fn new(size: u16) -> Self {
+        let desc_bytes = size_of::<VringDesc>() * size as usize;
+        let avail_bytes = size_of::<VringAvail>() + size_of::<u16>() * size as usize;
+        let used_offset = align_up(desc_bytes + avail_bytes, PAGE_SIZE);
+        let used_bytes = size_of::<VringUsed>() + size_of::<VringUsedElem>() * size as usize;
+        let total = align_up(used_offset + used_bytes, PAGE_SIZE);
+
+        let mem = crate::allocator::malloc(total, PAGE_SIZE);
+        unsafe { core::ptr::write_bytes(mem, 0, total) };
+
+        let desc = mem.cast::<VringDesc>();
+        let avail = unsafe { mem.add(desc_bytes) }.cast::<VringAvail>();
+        let used = unsafe { mem.add(used_offset) }.cast::<VringUsed>();
+
+        unsafe {
+            for i in 0..size {
+                (*desc.add(i as usize)).next = if i + 1 < size { i + 1 } else { 0 };
+            }
+        }
+
+        Self {
+            mem,
+            size,
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            last_used_idx: 0,
+            completed: BTreeSet::new(),
+        }
+    }
+
+    fn desc_table_addr(&self) -> u64 {
+        self.mem as u64
+    }
+
+    /// The available ring's `ring[size]` body sits immediately after its
+    /// header, so `self.avail.add(1)` (one `VringAvail`, not one byte)
+    /// lands exactly there.
+    fn avail_ring(&self) -> *mut u16 {
+        unsafe { self.avail.add(1).cast::<u16>() }
+    }
+
+    fn used_ring(&self) -> *mut VringUsedElem {
+        unsafe { self.used.add(1).cast::<VringUsedElem>() }
+    }
+
+    /// Builds the 3-descriptor chain (header, data buffer, status byte)
+    /// used by every `virtio_blk_req` out of the next three descriptors on
+    /// the free list, advances `free_head` past them, and publishes its
+    /// head into the available ring.
+    fn submit(&mut self, header: *const u8, data: *mut u8, data_len: u32, write: bool, status: *mut u8) -> u16 {
+        let head = self.free_head;
+        unsafe {
+            let header_desc = self.desc.add(head as usize);
+            (*header_desc).addr = header as u64;
+            (*header_desc).len = 16; // type(4) + reserved(4) + sector(8)
+            (*header_desc).flags = VRING_DESC_F_NEXT;
+            let data_idx = (*header_desc).next;
+
+            let data_desc = self.desc.add(data_idx as usize);
+            (*data_desc).addr = data as u64;
+            (*data_desc).len = data_len;
+            (*data_desc).flags = VRING_DESC_F_NEXT | if write { 0 } else { VRING_DESC_F_WRITE };
+            let status_idx = (*data_desc).next;
+
+            let status_desc = self.desc.add(status_idx as usize);
+            (*status_desc).addr = status as u64;
+            (*status_desc).len = 1;
+            (*status_desc).flags = VRING_DESC_F_WRITE;
+
+            self.free_head = (*status_desc).next;
+
+            let avail = &mut *self.avail;
+            let slot = avail.idx % self.size;
+            *self.avail_ring().add(slot as usize) = head;
+            fence(Ordering::SeqCst);
+            avail.idx = avail.idx.wrapping_add(1);
+        }
+        head
+    }
+
+    /// Scans the used ring for completions that showed up since the last
+    /// call, from either `on_interrupt` or `request`'s spin loop, and
+    /// folds their heads into `completed` rather than handing them back
+    /// directly -- a caller only cares about its own head, and the other
+    /// path calling this concurrently must not make it miss one.
+    fn drain_used(&mut self) {
+        unsafe {
+            let used = &*self.used;
+            while self.last_used_idx != used.idx {
+                let slot = self.last_used_idx % self.size;
+                let head = (*self.used_ring().add(slot as usize)).id as u16;
+                self.completed.insert(head);
+                self.last_used_idx = self.last_used_idx.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Removes `head` from `completed` if `drain_used` has already seen
+    /// it finish, whether that happened via this call's own polling or
+    /// the interrupt handler racing ahead of it.
+    fn take_completed(&mut self, head: u16) -> bool {
+        self.completed.remove(&head)
+    }
+}
+
+#[repr(C)]
+struct BlkRequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A virtio-blk device, discovered and negotiated over the legacy
+/// virtio-pci transport, exposing the same `BlockDevice` interface as
+/// the PATA driver so it can be `mount`ed identically.
+pub struct VirtioBlk {
+    io_base: u16,
+    queue: Mutex<VirtQueue>,
+}
+
+/// The probed device, kept reachable for `on_interrupt` to dispatch into
+/// once the IDT routes `InterruptVector::VirtioBlk` there -- `probe`'s
+/// caller hands the returned `&'static VirtioBlk` straight to `mount`,
+/// which takes ownership of *that* for the `BlockDevice` trait object,
+/// so without this static there'd be nothing left for the interrupt
+/// handler to call back into.
+static DEVICE: Mutex<Option<&'static VirtioBlk>> = Mutex::new(None);
+
+pub fn probe() -> Option<&'static VirtioBlk> {
+    let device: PciDevice = PciSearcher::new().vendor(VENDOR_ID).search().ok()?.first()?;
+    info!(
+        "virtio-blk found: {}.{}.{}",
+        device.bus, device.dev, device.func
+    );
+
+    let io_base = (device.read_bar(0) & !0x3) as u16;
+
+    unsafe {
+        outb(io_base + REG_DEVICE_STATUS, 0);
+        outb(io_base + REG_DEVICE_STATUS, status::ACKNOWLEDGE);
+        outb(io_base + REG_DEVICE_STATUS, status::ACKNOWLEDGE | status::DRIVER);
+
+        let device_features = inl(io_base + REG_DEVICE_FEATURES);
+        debug!("virtio-blk device features: {device_features:#010X}");
+        // No optional features (RO, SCSI passthrough, ...) are needed for
+        // plain block reads/writes.
+        outl(io_base + REG_GUEST_FEATURES, 0);
+
+        outb(
+            io_base + REG_DEVICE_STATUS,
+            status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK,
+        );
+        if inb(io_base + REG_DEVICE_STATUS) & status::FEATURES_OK == 0 {
+            warn!("virtio-blk: device rejected feature negotiation");
+            outb(io_base + REG_DEVICE_STATUS, status::FAILED);
+            return None;
+        }
+
+        outw(io_base + REG_QUEUE_SELECT, 0);
+        let negotiated_size = inw(io_base + REG_QUEUE_SIZE);
+        debug!("virtio-blk queue 0 size={negotiated_size}");
+        if negotiated_size == 0 {
+            warn!("virtio-blk: device reported queue 0 size=0");
+            outb(io_base + REG_DEVICE_STATUS, status::FAILED);
+            return None;
+        }
+
+        let queue = VirtQueue::new(negotiated_size);
+        outl(io_base + REG_QUEUE_ADDRESS, (queue.desc_table_addr() >> 12) as u32);
+
+        device.capabilities().for_each(|cap| {
+            let msg = Message::new()
+                .destionation_id(0xFF)
+                .interrupt_index(crate::interrupt::InterruptVector::VirtioBlk as u8)
+                .level(true)
+                .trigger_mode(true)
+                .delivery_mode(0);
+            if let Some(msi) = cap.msi() {
+                msi.enable(&msg);
+            } else if let Some(msi) = cap.msix() {
+                msi.enable(&msg);
+            }
+        });
+
+        outb(
+            io_base + REG_DEVICE_STATUS,
+            status::ACKNOWLEDGE | status::DRIVER | status::FEATURES_OK | status::DRIVER_OK,
+        );
+
+        let device: &'static VirtioBlk = Box::leak(Box::new(VirtioBlk {
+            io_base,
+            queue: Mutex::new(queue),
+        }));
+        *DEVICE.lock() = Some(device);
+        Some(device)
+    }
+}
+
+/// Dispatched from the IDT entry for `InterruptVector::VirtioBlk`, once a
+/// device has actually been `probe`d.
+pub fn on_interrupt() {
+    if let Some(device) = *DEVICE.lock() {
+        device.on_interrupt();
+    }
+}
+
+impl VirtioBlk {
+    fn request(&self, lba: u32, block: *mut u8, write: bool) -> Result<(), BlockError> {
+        let header = BlkRequestHeader {
+            kind: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+            reserved: 0,
+            sector: lba as u64,
+        };
+        let mut status_byte: u8 = 0xFF;
+
+        let head = {
+            let mut queue = self.queue.lock();
+            queue.submit(
+                &header as *const BlkRequestHeader as *const u8,
+                block,
+                SECTOR_SIZE as u32,
+                write,
+                &mut status_byte as *mut u8,
+            )
+        };
+
+        unsafe { outw(self.io_base + REG_QUEUE_NOTIFY, 0) };
+
+        // The real completion path runs off the virtqueue's MSI/MSI-X
+        // interrupt (`on_interrupt`); spin here too so a synchronous
+        // caller still observes a finished transfer. Both paths call
+        // `drain_used` rather than racing to read the used ring directly,
+        // so whichever one runs first never steals the other's head.
+        loop {
+            let mut queue = self.queue.lock();
+            queue.drain_used();
+            if queue.take_completed(head) {
+                break;
+            }
+            drop(queue);
+            core::hint::spin_loop();
+        }
+
+        if status_byte != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+
+    /// Called from the device's MSI/MSI-X handler: drains the used ring
+    /// so any request a caller is spinning on in `request` observes the
+    /// completion without needing to poll the device itself.
+    pub fn on_interrupt(&self) {
+        unsafe { inb(self.io_base + REG_ISR_STATUS) };
+        self.queue.lock().drain_used();
+    }
+}
+
+impl BlockDevice<SECTOR_SIZE> for VirtioBlk {
+    fn read_block(&self, lba: u32, buffer: &mut [Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        for (offset, block) in buffer.iter_mut().enumerate() {
+            self.request(lba + offset as u32, block.as_mut_ptr(), false)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u32, buffer: &[Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        for (offset, block) in buffer.iter().enumerate() {
+            let mut scratch = *block;
+            self.request(lba + offset as u32, scratch.as_mut_ptr(), true)?;
+        }
+        Ok(())
+    }
+}
+
+/// `probe` hands out `&'static VirtioBlk` (see `DEVICE`) rather than an
+/// owned `VirtioBlk`, so `mount` needs an impl that goes through the
+/// reference instead of consuming the device outright.
+impl BlockDevice<SECTOR_SIZE> for &'static VirtioBlk {
+    fn read_block(&self, lba: u32, buffer: &mut [Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        (**self).read_block(lba, buffer)
+    }
+
+    fn write_block(&self, lba: u32, buffer: &[Block<SECTOR_SIZE>]) -> Result<(), BlockError> {
+        (**self).write_block(lba, buffer)
+    }
+}
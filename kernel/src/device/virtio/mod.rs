@@ -0,0 +1,14 @@
+pub mod blk;
+
+/// Legacy virtio-pci device status bits (virtio 0.9.5 / "legacy" mode,
+/// which is what QEMU's `virtio-*-pci` devices speak unless `disable-legacy`
+/// is set).
+pub mod status {
+    pub const ACKNOWLEDGE: u8 = 1;
+    pub const DRIVER: u8 = 2;
+    pub const DRIVER_OK: u8 = 4;
+    pub const FEATURES_OK: u8 = 8;
+    pub const FAILED: u8 = 128;
+}
+
+pub const VENDOR_ID: u16 = 0x1AF4;
@@ -0,0 +1,52 @@
+use crate::device::hdd::{Block, BlockDevice, BlockError};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// Wraps the bootloader-provided initramfs image as a read-only block
+/// device so it can be `mount`ed under a name (conventionally `initrd`)
+/// exactly like a PATA drive, which lets `open`/`open_dir` work against
+/// packed-in files before any disk has been found.
+pub struct InitrdDevice {
+    base: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for InitrdDevice {}
+unsafe impl Sync for InitrdDevice {}
+
+impl InitrdDevice {
+    /// # Safety
+    /// `base`/`len` must describe the memory region the bootloader
+    /// loaded the ramdisk image into, and that region must stay mapped
+    /// and untouched for the device's lifetime.
+    pub unsafe fn new(base: u64, len: usize) -> Self {
+        Self {
+            base: base as *const u8,
+            len,
+        }
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.len / BLOCK_SIZE) as u32
+    }
+}
+
+impl BlockDevice<BLOCK_SIZE> for InitrdDevice {
+    fn read_block(&self, lba: u32, buffer: &mut [Block<BLOCK_SIZE>]) -> Result<(), BlockError> {
+        for (offset, block) in buffer.iter_mut().enumerate() {
+            let lba = lba + offset as u32;
+            if lba >= self.block_count() {
+                return Err(BlockError::DeviceError);
+            }
+            let src = unsafe { self.base.add(lba as usize * BLOCK_SIZE) };
+            unsafe { core::ptr::copy_nonoverlapping(src, block.as_mut_ptr(), BLOCK_SIZE) };
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, _lba: u32, _buffer: &[Block<BLOCK_SIZE>]) -> Result<(), BlockError> {
+        // The ramdisk image is a read-only snapshot handed to us by the
+        // bootloader; there's nothing to write it back to.
+        Err(BlockError::DeviceError)
+    }
+}
@@ -0,0 +1,146 @@
+use core::arch::asm;
+use core::mem::size_of;
+
+/// Segment selectors, in GDT order. User selectors are grouped as
+/// data-then-code so `STAR` (whose SYSRET base yields the ring-3 SS at
+/// `base + 8` and CS at `base + 16`, i.e. `user_data` and `user_data + 8`)
+/// only has to encode one base index.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = (0x18) | 3;
+pub const USER_CODE_SELECTOR: u16 = (0x20) | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+
+const GDT_ENTRY_COUNT: usize = 7; // null, kcode, kdata, udata, ucode, tss(lo), tss(hi)
+
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    pub rsp0: u64,
+    rsp1: u64,
+    rsp2: u64,
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            reserved0: 0,
+            rsp0: 0,
+            rsp1: 0,
+            rsp2: 0,
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+static mut GDT: [u64; GDT_ENTRY_COUNT] = [0; GDT_ENTRY_COUNT];
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+const fn code_segment(dpl: u64, long_mode: bool) -> u64 {
+    let mut entry = 0x00_0F_9A_00_0000_FFFFu64; // present, type=code, readable
+    entry |= dpl << 45;
+    if long_mode {
+        entry |= 1 << 53;
+    }
+    entry
+}
+
+const fn data_segment(dpl: u64) -> u64 {
+    let mut entry = 0x00_0F_92_00_0000_FFFFu64; // present, type=data, writable
+    entry |= dpl << 45;
+    entry
+}
+
+/// Installs the kernel code/data descriptors plus the ring-3 user
+/// code/data descriptors and the TSS used to hold the per-task kernel
+/// stack pointer (`RSP0`) that the CPU loads on an interrupt from ring 3
+/// (`SYSCALL` does not consult it; see `task::syscall`).
+pub fn init_gdt() {
+    unsafe {
+        GDT[0] = 0;
+        GDT[1] = code_segment(0, true);
+        GDT[2] = data_segment(0);
+        GDT[3] = data_segment(3);
+        GDT[4] = code_segment(3, true);
+
+        let tss_base = core::ptr::addr_of!(TSS) as u64;
+        let tss_limit = (size_of::<TaskStateSegment>() - 1) as u64;
+        // System descriptor layout: Base[23:0] at bits 16-39, the access
+        // byte (present, type=0x9, DPL=0) at bits 40-47, and Base[31:24]
+        // at bits 56-63 -- not bits 56-63 of the *input* `0x89` shifted
+        // wholesale, which would leave the real access byte zero (not
+        // present) and never encode the top base byte at all.
+        let low = 0x0000_8900_0000_0000u64
+            | ((tss_base & 0xFF_FFFF) << 16)
+            | (((tss_base >> 24) & 0xFF) << 56)
+            | tss_limit;
+        let high = tss_base >> 32;
+        GDT[5] = low;
+        GDT[6] = high;
+
+        let pointer = GdtPointer {
+            limit: (size_of::<[u64; GDT_ENTRY_COUNT]>() - 1) as u16,
+            base: core::ptr::addr_of!(GDT) as u64,
+        };
+
+        asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack));
+        reload_segments();
+
+        asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nostack));
+    }
+}
+
+unsafe fn reload_segments() {
+    asm!(
+        "push {code}",
+        "lea {tmp}, [2f + rip]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ax, {data:x}",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        code = in(reg) KERNEL_CODE_SELECTOR as u64,
+        data = in(reg) KERNEL_DATA_SELECTOR,
+        tmp = lateout(reg) _,
+        options(preserves_flags),
+    );
+}
+
+/// Points the TSS's ring-0 stack at `stack_top` so the next interrupt
+/// taken from a ring-3 task lands on that task's kernel stack instead of
+/// whichever task ran last. `SYSCALL` does not read `RSP0`.
+pub fn set_kernel_stack(stack_top: u64) {
+    unsafe {
+        TSS.rsp0 = stack_top;
+    }
+}
+
+/// The BSP's `(limit, base)` GDT descriptor, shared by every AP: the real
+/// mode trampoline in `task::smp` needs these two values to `lgdt` before
+/// it can switch to protected mode, and there's no other way to reach
+/// them from outside this module.
+pub(crate) fn descriptor_table_pointer() -> (u16, u64) {
+    (
+        (size_of::<[u64; GDT_ENTRY_COUNT]>() - 1) as u16,
+        core::ptr::addr_of!(GDT) as u64,
+    )
+}
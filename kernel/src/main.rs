@@ -10,6 +10,8 @@ use bootloader::{BootInfo, FrameBufferConfig, PixelFormat};
 use kernel::{
     acpi,
     allocator::init_heap,
+    cmdline::CommandLine,
+    config::ConfigStore,
     console::{init_console, Console},
     device::{
         driver::keyboard::{get_code, getch, Keyboard},
@@ -28,7 +30,7 @@ use kernel::{
     entry_point,
     float::set_ts,
     font::write_ascii,
-    fs::{self, dev_list, format_by_name, init_fs, mount, open, open_dir},
+    fs::{self, dev_list, format_by_name, init_fs, initrd::InitrdDevice, mount, open, open_dir},
     gdt::init_gdt,
     graphic::{get_graphic, init_graphic, GraphicWriter, PixelColor, PIXEL_WRITER},
     interrupt::{
@@ -38,7 +40,7 @@ use kernel::{
     ioapic,
     page::init_page,
     print, println,
-    task::{create_task, exit, idle, init_task, running_task, schedule, TaskFlags},
+    task::{create_task, exit, idle, init_smp, init_task, running_task, schedule, TaskFlags},
     timer::init_pm,
 };
 use log::{debug, error, info, trace, warn};
@@ -74,12 +76,38 @@ fn kernel_main(boot_info: BootInfo) {
     init_fs();
     info!("Root File System Initialized");
 
+    // `boot_info.cmdline`/`boot_info.initrd` are not yet part of this
+    // workspace's `bootloader` crate (it currently only hands over
+    // `frame_config`/`memory_map`/`rsdp`, see the imports above) -- that
+    // crate lives outside this source chunk, so it can't be extended
+    // from here. Landing this feature for real means adding
+    // `cmdline: &'static str` and `initrd: Option<(u64, usize)>` to its
+    // `BootInfo` and having the bootloader stage populate them from the
+    // Multiboot2/EFI command line and initrd module, respectively.
+    let cmdline = CommandLine::parse(boot_info.cmdline);
+    info!("Kernel command line: {}", boot_info.cmdline);
+
+    if let Some((base, len)) = boot_info.initrd {
+        let initrd = unsafe { InitrdDevice::new(base, len) };
+        match mount(initrd, "initrd") {
+            Ok(fs_count) => info!("initrd mounted, fs_count={fs_count}"),
+            Err(reason) => info!("initrd mount failed: {reason}"),
+        }
+    }
+
     // Do Not Use
     // set_ts();
     // info!("Lazy FP Enable");
 
-    acpi::initialize(boot_info.rsdp);
-    info!("ACPI Initialized");
+    if !cmdline.is_set("noacpi") {
+        acpi::initialize(boot_info.rsdp);
+        info!("ACPI Initialized");
+
+        init_smp();
+        info!("SMP Initialized");
+    } else {
+        info!("ACPI skipped (noacpi), staying uniprocessor");
+    }
 
     ioapic::init();
     info!("I/O APIC Initialized");
@@ -151,7 +179,9 @@ fn kernel_main(boot_info: BootInfo) {
                 xhc.reset_port().expect("xHCI Port Reset Failed");
                 regist_controller(xhc);
             });
-            create_task(TaskFlags::new(), print_input as u64, 0, 0);
+            if let Err(()) = create_task(TaskFlags::new(), print_input as u64, 0, 0) {
+                warn!("print_input task could not be created");
+            }
         }
         None => {}
     }
@@ -169,9 +199,12 @@ fn kernel_main(boot_info: BootInfo) {
                 ide_dev.bus, ide_dev.dev, ide_dev.func
             );
             init_pata();
+            let root = cmdline.get("root");
             for i in 0..4 {
-                if i == 0 {
-                    continue;
+                match root {
+                    Some(root) if root != format!("pata{i}") => continue,
+                    None if i == 0 => continue,
+                    _ => {}
                 }
                 if let Ok(hdd) = get_device(i) {
                     info!("PATA:{i} Detected");
@@ -188,6 +221,19 @@ fn kernel_main(boot_info: BootInfo) {
                             }
                         }
                     }
+                    let config = ConfigStore::open(&dev_name);
+                    match config.get("root_device") {
+                        Some(value) => info!(
+                            "config: root_device={}",
+                            String::from_utf8_lossy(&value)
+                        ),
+                        None => {
+                            if config.set("root_device", dev_name.as_bytes()).is_err() {
+                                warn!("config: failed to persist default root device");
+                            }
+                        }
+                    }
+
                     let mut count = 0;
                     let file = open(&dev_name, 0, "/file", b"w").expect("File Open Failed");
                     let root = open_dir(&dev_name, 0, "/", b"r")
@@ -208,6 +254,21 @@ fn kernel_main(boot_info: BootInfo) {
         }
         None => {}
     }
+
+    if let Some(virtio_hdd) = kernel::device::virtio::blk::probe() {
+        let dev_name = "virtio0";
+        if let Ok(fs_count) = mount(virtio_hdd, dev_name) {
+            info!("virtio-blk mounted, fs_count={fs_count}");
+            if fs_count == 0 {
+                if let Err(reason) = format_by_name(dev_name, 1024 * 1024 * 10 / 512) {
+                    info!("virtio-blk format failed");
+                    info!("{}", reason);
+                } else {
+                    info!("virtio-blk formated");
+                }
+            }
+        }
+    }
 }
 
 fn print_input() {
@@ -331,28 +392,34 @@ fn test_hdd_rw() {
 
 fn test() {
     for i in 0..50 {
-        create_task(
+        if let Err(()) = create_task(
             TaskFlags::new().thread().set_priority(66).clone(),
             test_thread as u64,
             0,
             0,
-        );
+        ) {
+            warn!("test_thread task {i} (priority 66) could not be created");
+        }
     }
     for i in 0..50 {
-        create_task(
+        if let Err(()) = create_task(
             TaskFlags::new().thread().set_priority(130).clone(),
             test_thread as u64,
             0,
             0,
-        );
+        ) {
+            warn!("test_thread task {i} (priority 130) could not be created");
+        }
     }
     for i in 0..50 {
-        create_task(
+        if let Err(()) = create_task(
             TaskFlags::new().thread().set_priority(200).clone(),
             test_thread as u64,
             0,
             0,
-        );
+        ) {
+            warn!("test_thread task {i} (priority 200) could not be created");
+        }
     }
     loop {
         schedule();
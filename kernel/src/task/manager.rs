@@ -17,7 +17,7 @@ pub struct TaskManager {
 }
 
 impl TaskManager {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             empty_queue: RawQueue::new(),
             task_map: BTreeMap::new(),
@@ -27,7 +27,9 @@ impl TaskManager {
         }
     }
 
-    pub fn allocate(&mut self) -> Result<&'static mut Task, ()> {
+    /// `user` records whether the task runs in ring 3, so `free` knows
+    /// whether it owns a separate user-stack mapping to tear down.
+    pub fn allocate(&mut self, user: bool) -> Result<&'static mut Task, ()> {
         const TASK_SIZE: usize = size_of::<Task>();
         if self.use_count >= TASKPOOL_SIZE {
             return Err(());
@@ -46,6 +48,7 @@ impl TaskManager {
             }
         };
         task.set_id(self.alloc_count as u64);
+        task.set_user(user);
         self.task_map.insert(task.id(), NonNull::new(task).unwrap());
 
         self.alloc_count = self.alloc_count.wrapping_add(1);
@@ -56,6 +59,7 @@ impl TaskManager {
     pub fn free(&mut self, task: &mut Task) {
         self.task_map.remove(&task.id());
 
+        task.teardown_stacks();
         task.set_parent(None);
         task.set_child(None);
         task.set_sibling(None);
@@ -0,0 +1,482 @@
+pub mod manager;
+pub mod smp;
+mod syscall;
+
+pub use smp::init_smp;
+pub use syscall::{init_syscall, syscall_handler, SyscallNumber};
+
+use core::mem::offset_of;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    allocator::malloc,
+    collections::list::RawNode,
+    gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+};
+
+use manager::TaskManager;
+
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+pub const USER_STACK_SIZE: usize = 1024 * 1024;
+
+static TASK_MANAGER: Mutex<TaskManager> = Mutex::new(TaskManager::new());
+
+/// Build-up flags for `create_task`. Chained setters return `&mut Self` so
+/// callers finish with `.clone()` to hand an owned value to `create_task`,
+/// e.g. `TaskFlags::new().thread().set_priority(66).clone()`.
+#[derive(Clone, Copy)]
+pub struct TaskFlags {
+    bits: u32,
+    priority: u8,
+}
+
+impl TaskFlags {
+    const THREAD: u32 = 1 << 0;
+    const USER: u32 = 1 << 1;
+
+    pub fn new() -> Self {
+        Self {
+            bits: 0,
+            priority: 128,
+        }
+    }
+
+    pub fn thread(&mut self) -> &mut Self {
+        self.bits |= Self::THREAD;
+        self
+    }
+
+    /// Marks the task to run in ring 3 on its own user stack, entered via
+    /// `iretq` in `task_trampoline` and reaching the kernel through
+    /// `SYSCALL`. The user stack comes from the kernel heap; the task
+    /// shares the kernel's address space.
+    pub fn user(&mut self) -> &mut Self {
+        self.bits |= Self::USER;
+        self
+    }
+
+    pub fn set_priority(&mut self, priority: u8) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn is_thread(&self) -> bool {
+        self.bits & Self::THREAD != 0
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.bits & Self::USER != 0
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Context {
+    pub fn empty() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+}
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FPUContext {
+    data: [u8; 512],
+}
+
+impl FPUContext {
+    pub fn new() -> Self {
+        Self { data: [0; 512] }
+    }
+}
+
+pub struct Task {
+    id: u64,
+    flags: TaskFlags,
+    user: bool,
+    context: Context,
+    fpu_context: FPUContext,
+    kernel_stack: *mut u8,
+    user_stack: Option<*mut u8>,
+    /// The saved `rsp` `switch_context` should load to resume this task:
+    /// either a real suspension point left by a previous `switch_context`
+    /// call, or (before the task has ever run) the fake frame
+    /// `prime_switch_frame` built that lands in `task_trampoline` instead.
+    switch_rsp: u64,
+    /// Guards `switch_rsp` against being read by another core before the
+    /// core switching this task out has finished writing it: false from
+    /// the moment this task is picked to be switched away from until
+    /// `switch_context` records its new resume point.
+    parked: AtomicBool,
+    parent: Option<NonNull<Task>>,
+    child: Option<NonNull<Task>>,
+    sibling: Option<NonNull<Task>>,
+    prev: Option<NonNull<Task>>,
+    next: Option<NonNull<Task>>,
+    vm: Option<crate::vm::Vm>,
+}
+
+impl Task {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.user
+    }
+
+    pub fn set_user(&mut self, user: bool) {
+        self.user = user;
+    }
+
+    pub fn set_parent(&mut self, parent: Option<NonNull<Task>>) {
+        self.parent = parent;
+    }
+
+    pub fn set_child(&mut self, child: Option<NonNull<Task>>) {
+        self.child = child;
+    }
+
+    pub fn set_sibling(&mut self, sibling: Option<NonNull<Task>>) {
+        self.sibling = sibling;
+    }
+
+    pub fn set_prev(&mut self, prev: Option<NonNull<Task>>) {
+        self.prev = prev;
+    }
+
+    pub fn set_next(&mut self, next: Option<NonNull<Task>>) {
+        self.next = next;
+    }
+
+    pub fn context(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    pub fn fpu_context(&mut self) -> &mut FPUContext {
+        &mut self.fpu_context
+    }
+
+    pub fn set_vm(&mut self, vm: crate::vm::Vm) {
+        self.vm = Some(vm);
+    }
+
+    pub fn vm(&mut self) -> Option<&mut crate::vm::Vm> {
+        self.vm.as_mut()
+    }
+
+    /// Releases the stack(s) this task owns. User tasks get both a kernel
+    /// stack (used while trapped via syscall/interrupt) and a separate
+    /// ring-3 stack; kernel tasks only ever touch the former.
+    pub(crate) fn teardown_stacks(&mut self) {
+        if self.user {
+            if let Some(user_stack) = self.user_stack.take() {
+                crate::allocator::free(user_stack);
+            }
+        }
+        if !self.kernel_stack.is_null() {
+            crate::allocator::free(self.kernel_stack);
+            self.kernel_stack = core::ptr::null_mut();
+        }
+        if let Some(mut vm) = self.vm.take() {
+            vm.release();
+        }
+    }
+}
+
+impl RawNode<Task> for Task {
+    fn prev(&self) -> Option<NonNull<Task>> {
+        self.prev
+    }
+
+    fn set_prev(&mut self, prev: Option<NonNull<Task>>) {
+        self.prev = prev;
+    }
+
+    fn next(&self) -> Option<NonNull<Task>> {
+        self.next
+    }
+
+    fn set_next(&mut self, next: Option<NonNull<Task>>) {
+        self.next = next;
+    }
+}
+
+pub fn init_task() {
+    init_syscall();
+}
+
+/// Allocates a task, wires up its entry point, a kernel stack and (for
+/// ring-3 tasks) a user stack, and links it into the ready queue. `address` is where the task starts executing; for user tasks
+/// this must be a ring-3 mapped address rather than a kernel function.
+pub fn create_task(flags: TaskFlags, address: u64, arg1: u64, arg2: u64) -> Result<u64, ()> {
+    let mut manager = TASK_MANAGER.lock();
+    let task = manager.allocate(flags.is_user())?;
+
+    task.flags = flags;
+    task.vm = None;
+    task.kernel_stack = malloc(KERNEL_STACK_SIZE, 16);
+    task.user_stack = if flags.is_user() {
+        Some(malloc(USER_STACK_SIZE, 16))
+    } else {
+        None
+    };
+
+    let (cs, ss, rsp) = if flags.is_user() {
+        let user_stack = task.user_stack.unwrap();
+        (
+            USER_CODE_SELECTOR as u64,
+            USER_DATA_SELECTOR as u64,
+            unsafe { user_stack.add(USER_STACK_SIZE) } as u64,
+        )
+    } else {
+        (
+            KERNEL_CODE_SELECTOR as u64,
+            KERNEL_DATA_SELECTOR as u64,
+            unsafe { task.kernel_stack.add(KERNEL_STACK_SIZE) } as u64,
+        )
+    };
+
+    let context = task.context();
+    *context = Context::empty();
+    context.rdi = arg1;
+    context.rsi = arg2;
+    context.rip = address;
+    context.rflags = 0x202;
+    context.cs = cs;
+    context.ss = ss;
+    context.rsp = rsp;
+
+    let kernel_stack_top = unsafe { task.kernel_stack.add(KERNEL_STACK_SIZE) } as u64;
+    task.switch_rsp = unsafe { prime_switch_frame(kernel_stack_top, &task.context as *const Context) };
+    task.parked = AtomicBool::new(true);
+
+    let id = task.id();
+    let cpu = smp::pick_cpu();
+    smp::enqueue(cpu, task);
+    Ok(id)
+}
+
+/// Lays out the fake `switch_context` frame (the callee-saved registers
+/// it pops, plus a return address) a task needs on its kernel stack
+/// before it has ever run, so the first `schedule()` that picks it can
+/// resume it exactly like any other suspended task -- `ret`-ing into
+/// `task_trampoline`, which reads `rbx` (one of those "saved" registers,
+/// repurposed here to carry the `Context` pointer) to find the real
+/// entry point.
+unsafe fn prime_switch_frame(kernel_stack_top: u64, context: *const Context) -> u64 {
+    const WORDS: u64 = 7; // r15, r14, r13, r12, rbx, rbp, return address
+    let frame = (kernel_stack_top - WORDS * 8) as *mut u64;
+    unsafe {
+        frame.add(0).write(0); // r15
+        frame.add(1).write(0); // r14
+        frame.add(2).write(0); // r13
+        frame.add(3).write(0); // r12
+        frame.add(4).write(context as u64); // rbx
+        frame.add(5).write(0); // rbp
+        frame.add(6).write(task_trampoline as u64); // return address
+    }
+    frame as u64
+}
+
+/// Entered (via `ret`, from `switch_context`) the first time a task is
+/// switched to, with `rbx` holding a pointer to its `Context`. Kernel
+/// tasks just jump straight to `Context::rip` on the stack `switch_context`
+/// already landed on; ring-3 tasks need an `iretq` to actually drop
+/// privilege, which `Context`'s `rip`/`cs`/`rflags`/`rsp`/`ss` tail is laid
+/// out to feed directly, in that exact order.
+#[unsafe(naked)]
+unsafe extern "C" fn task_trampoline() -> ! {
+    core::arch::naked_asm!(
+        "mov rax, [rbx + {cs_off}]",
+        "cmp rax, {kernel_cs}",
+        "je 1f",
+        "mov rdi, [rbx + {rdi_off}]",
+        "mov rsi, [rbx + {rsi_off}]",
+        "lea rsp, [rbx + {rip_off}]",
+        "iretq",
+        "1:",
+        "mov rdi, [rbx + {rdi_off}]",
+        "mov rsi, [rbx + {rsi_off}]",
+        "mov rax, [rbx + {rip_off}]",
+        "jmp rax",
+        cs_off = const offset_of!(Context, cs),
+        rip_off = const offset_of!(Context, rip),
+        rdi_off = const offset_of!(Context, rdi),
+        rsi_off = const offset_of!(Context, rsi),
+        kernel_cs = const KERNEL_CODE_SELECTOR as u64,
+    )
+}
+
+/// Switches the running kernel stack from whatever task owns `old_rsp`
+/// (or no task at all, if `old_rsp` is null -- this core was idle) to
+/// `new_rsp`. Only the callee-saved registers need saving: every other
+/// register is already dead across a Rust function call per the SysV
+/// ABI, so resuming a task later just means returning into wherever its
+/// own call to `schedule` left off. Sets `*old_parked` true right after
+/// `old_rsp` is safely recorded, so another core spinning on it (see
+/// `schedule`) knows it's safe to load.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(old_rsp: *mut u64, old_parked: *const AtomicBool, new_rsp: u64) {
+    core::arch::naked_asm!(
+        "test rdi, rdi",
+        "jz 2f",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov byte ptr [rsi], 1",
+        "2:",
+        "mov rsp, rdx",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+/// Creates a task whose body is a sandboxed VM program instead of a
+/// native function pointer. The native context set up by `create_task`
+/// is never actually entered; `schedule()` special-cases VM-owning
+/// tasks and steps the VM directly rather than context-switching to
+/// one.
+pub fn create_vm_task(flags: TaskFlags, code: &'static [u8], data_len: usize) -> Result<u64, ()> {
+    let id = create_task(flags, vm_idle as u64, 0, 0)?;
+    let mut manager = TASK_MANAGER.lock();
+    if let Some(task) = manager.get(id) {
+        task.set_vm(crate::vm::Vm::new(code, data_len));
+    }
+    Ok(id)
+}
+
+fn vm_idle() {
+    loop {
+        schedule();
+    }
+}
+
+/// Returns the task running on *this* core. Each CPU has its own notion
+/// of "the running task", so two cores calling this concurrently see
+/// different answers.
+pub fn running_task() -> Option<&'static Task> {
+    smp::running_task()
+}
+
+/// Picks the next ready task off this core's own queue and switches to
+/// it, pointing the TSS's `RSP0` at its kernel stack so a subsequent trap
+/// from ring 3 lands somewhere valid. The task this core was running
+/// before the call (if any) is pushed back onto the ready queue -- it
+/// would otherwise simply be dropped after a single run -- and, for a
+/// native (non-VM) task, an actual register/stack switch carries it out:
+/// `vm.run_slice()` alone only ever cooperatively steps a VM-owning
+/// task's bytecode from whichever native context called `schedule`, so
+/// it doesn't need one. Also takes the opportunity to migrate a task off
+/// the busiest core if this one has gone idle.
+pub fn schedule() {
+    let Some(mut next) = smp::local_ready_pop().map(NonNull::from) else {
+        smp::set_running(None);
+        smp::balance();
+        return;
+    };
+
+    let previous = smp::take_running();
+    let task = unsafe { next.as_mut() };
+    syscall::set_kernel_stack(unsafe { task.kernel_stack.add(KERNEL_STACK_SIZE) } as u64);
+    smp::set_running(Some(next));
+
+    if let Some(vm) = task.vm() {
+        requeue(previous);
+        vm.run_slice();
+        return;
+    }
+
+    // `next` might be resumed by this call before the core that's about
+    // to switch it out (if any) has finished recording its resume point.
+    while !task.parked.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let new_rsp = task.switch_rsp;
+
+    match previous {
+        Some(mut previous) => {
+            let previous_task = unsafe { previous.as_mut() };
+            previous_task.parked.store(false, Ordering::Release);
+            smp::requeue(previous_task);
+            unsafe {
+                switch_context(
+                    &mut previous_task.switch_rsp,
+                    &previous_task.parked,
+                    new_rsp,
+                );
+            }
+        }
+        None => unsafe { switch_context(core::ptr::null_mut(), core::ptr::null(), new_rsp) },
+    }
+}
+
+/// Pushes the task this core was running before the current `schedule()`
+/// call back onto the ready queue, used on the path that just steps a
+/// VM-owning task's bytecode rather than context-switching to it.
+fn requeue(previous: Option<NonNull<Task>>) {
+    if let Some(mut previous) = previous {
+        smp::requeue(unsafe { previous.as_mut() });
+    }
+}
+
+pub fn idle() {
+    loop {
+        schedule();
+    }
+}
+
+/// Tears down the task currently running on this core and switches away
+/// from it. Never returns to the caller.
+pub fn exit() -> ! {
+    if let Some(mut running) = smp::take_running() {
+        let mut manager = TASK_MANAGER.lock();
+        let task = unsafe { running.as_mut() };
+        manager.free(task);
+    }
+    loop {
+        schedule();
+    }
+}
@@ -0,0 +1,300 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    arch::{asm, global_asm},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use log::info;
+use spin::Mutex;
+
+use crate::{
+    acpi,
+    collections::queue::RawQueue,
+    gdt,
+    gdt::init_gdt,
+    interrupt::{
+        apic::{LocalAPICId, LocalAPICRegisters},
+        init_idt, InterruptVector,
+    },
+};
+
+use super::Task;
+
+/// Real-mode trampoline every AP starts executing at after SIPI. It runs
+/// out of identity-mapped low memory (below 1MiB, required by the SIPI
+/// vector-number-as-page-address encoding), loads the BSP's GDT and page
+/// tables -- both already built and pointing at addresses valid from
+/// any core; the IDT is loaded later by `ap_long_mode_entry` -- switches on `CR0.PE`/`CR4.PAE`/`EFER.LME`/`CR0.PG` in that
+/// order, and far-jumps into `ap_long_mode_entry`, a 64-bit Rust function.
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+global_asm!(
+    ".section .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    "ap_trampoline_start:",
+    ".code16",
+    "cli",
+    "xor ax, ax",
+    "mov ds, ax",
+    "lgdt [{trampoline_base} + ap_gdt_ptr - ap_trampoline_start]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    "ljmp 0x08, {trampoline_base} + ap_protected - ap_trampoline_start",
+    ".code32",
+    "ap_protected:",
+    "mov eax, cr4",
+    "or eax, 1 << 5", // PAE
+    "mov cr4, eax",
+    "mov eax, [{trampoline_base} + ap_cr3 - ap_trampoline_start]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080", // EFER
+    "rdmsr",
+    "or eax, 1 << 8", // LME
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, 1 << 31", // PG
+    "mov cr0, eax",
+    "ljmp 0x08, {trampoline_base} + ap_long_mode - ap_trampoline_start",
+    ".code64",
+    "ap_long_mode:",
+    "mov rax, [{trampoline_base} + ap_entry_fn - ap_trampoline_start]",
+    "jmp rax",
+    "ap_gdt_ptr:",
+    ".space 10",
+    "ap_cr3:",
+    ".space 8",
+    "ap_entry_fn:",
+    ".space 8",
+    "ap_trampoline_end:",
+    trampoline_base = const TRAMPOLINE_BASE,
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_gdt_ptr: u8;
+    static ap_cr3: u8;
+    static ap_entry_fn: u8;
+}
+
+/// Copies the trampoline from wherever the linker placed it (`.rodata`,
+/// not necessarily anywhere near `TRAMPOLINE_BASE`) down to the fixed
+/// low-memory address every AP is pointed at by its SIPI, and fills in
+/// the three parameter slots it reads on the way up: the BSP's GDT
+/// pointer (real mode), the current (BSP's) `CR3` (protected mode), and
+/// the address of `ap_long_mode_entry` (long mode). Must run once, before the first `start_ap`, on
+/// the BSP.
+unsafe fn prepare_trampoline() {
+    let start = core::ptr::addr_of!(ap_trampoline_start) as usize;
+    let end = core::ptr::addr_of!(ap_trampoline_end) as usize;
+    let len = end - start;
+    let dest = TRAMPOLINE_BASE as *mut u8;
+
+    core::ptr::copy_nonoverlapping(start as *const u8, dest, len);
+
+    let gdt_off = core::ptr::addr_of!(ap_gdt_ptr) as usize - start;
+    let cr3_off = core::ptr::addr_of!(ap_cr3) as usize - start;
+    let entry_off = core::ptr::addr_of!(ap_entry_fn) as usize - start;
+
+    let (limit, base) = gdt::descriptor_table_pointer();
+    dest.add(gdt_off).cast::<u16>().write_unaligned(limit);
+    dest.add(gdt_off + 2).cast::<u64>().write_unaligned(base);
+
+    let cr3: u64;
+    asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    dest.add(cr3_off).cast::<u64>().write_unaligned(cr3);
+
+    dest.add(entry_off)
+        .cast::<u64>()
+        .write_unaligned(ap_long_mode_entry as u64);
+}
+
+/// A per-CPU ready queue, keyed by Local APIC ID. Tasks are allocated out
+/// of the single shared `TaskManager` pool (`task_map` there already
+/// doubles as the cross-CPU lookup table `get` uses), but which CPU
+/// actually runs a ready task is decided here. A core with an empty
+/// queue just keeps calling `schedule()` (that's `idle()`), which is
+/// also where it tries to steal work via `balance()`.
+struct Core {
+    ready: RawQueue<Task>,
+}
+
+static CORES: Mutex<BTreeMap<u32, Core>> = Mutex::new(BTreeMap::new());
+static RUNNING: Mutex<BTreeMap<u32, NonNull<Task>>> = Mutex::new(BTreeMap::new());
+static BSP_ID: AtomicU32 = AtomicU32::new(0);
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+fn current_cpu() -> u32 {
+    LocalAPICRegisters::default().id().0
+}
+
+/// Parses the ACPI MADT for Local APIC IDs, starts every AP found there
+/// via the INIT-SIPI-SIPI sequence, and gives each core (BSP included)
+/// its own ready queue. Must run after `init_gdt`/`init_idt`/`init_page`/
+/// `acpi::initialize` on the boot processor.
+pub fn init_smp() {
+    let bsp = current_cpu();
+    BSP_ID.store(bsp, Ordering::SeqCst);
+    register_core(bsp);
+
+    let apic_ids: Vec<LocalAPICId> = acpi::madt().local_apic_ids().collect();
+    info!("SMP: {} CPU(s) reported by MADT", apic_ids.len());
+
+    if apic_ids.iter().any(|id| id.0 != bsp) {
+        unsafe { prepare_trampoline() };
+    }
+
+    for id in apic_ids {
+        if id.0 == bsp {
+            continue;
+        }
+        start_ap(id);
+    }
+}
+
+fn register_core(id: u32) {
+    CORES.lock().insert(id, Core { ready: RawQueue::new() });
+}
+
+/// Sends INIT, then two SIPIs at 10ms/200us spacing per the MP
+/// specification, pointing the AP at `TRAMPOLINE_BASE >> 12` as its
+/// start-of-execution vector.
+fn start_ap(id: LocalAPICId) {
+    let apic = LocalAPICRegisters::default();
+    let before = STARTED.load(Ordering::SeqCst);
+
+    apic.send_init_ipi(id);
+    busy_wait_us(10_000);
+    apic.send_sipi(id, (TRAMPOLINE_BASE >> 12) as u8);
+    busy_wait_us(200);
+    apic.send_sipi(id, (TRAMPOLINE_BASE >> 12) as u8);
+
+    for _ in 0..1_000_000 {
+        if STARTED.load(Ordering::SeqCst) != before {
+            register_core(id.0);
+            info!("SMP: CPU {} online", id.0);
+            return;
+        }
+    }
+    log::warn!("SMP: CPU {} did not respond to SIPI", id.0);
+}
+
+fn busy_wait_us(us: u64) {
+    for _ in 0..(us * 100) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Rust-side AP entry point, jumped to from the trampoline's long-mode
+/// stub once paging is live. Re-runs the per-core setup a single boot
+/// processor normally only does once, then folds into the idle loop.
+#[no_mangle]
+extern "C" fn ap_long_mode_entry() -> ! {
+    init_gdt();
+    init_idt();
+    // STAR/LSTAR/SFMASK and KERNEL_GS_BASE are per-CPU MSRs; the BSP
+    // running this once in `init_task` would leave every AP unable to
+    // take a SYSCALL at all.
+    super::init_syscall();
+    STARTED.fetch_add(1, Ordering::SeqCst);
+    super::idle();
+}
+
+/// Moves one ready task off whichever core has the longest queue onto
+/// the core with the shortest one. Called from `schedule()` on any core
+/// whose own ready queue is empty; a core with nothing to run just keeps
+/// calling it.
+pub fn balance() {
+    let mut cores = CORES.lock();
+    let (busiest, _) = match cores.iter().max_by_key(|(_, core)| core.ready.len()) {
+        Some(entry) => (*entry.0, ()),
+        None => return,
+    };
+    let idlest = match cores.iter().min_by_key(|(_, core)| core.ready.len()) {
+        Some((id, _)) => *id,
+        None => return,
+    };
+    if busiest == idlest {
+        return;
+    }
+
+    let Some(busiest_core) = cores.get_mut(&busiest) else {
+        return;
+    };
+    if busiest_core.ready.len() <= 1 {
+        return;
+    }
+    if let Some(task) = busiest_core.ready.pop() {
+        if let Some(idlest_core) = cores.get_mut(&idlest) {
+            idlest_core.ready.push(task);
+            request_reschedule(idlest);
+        }
+    }
+}
+
+/// Picks a target CPU for a newly created task: the core with the
+/// shortest ready queue, breaking ties toward the lowest Local APIC ID.
+pub fn pick_cpu() -> u32 {
+    let cores = CORES.lock();
+    cores
+        .iter()
+        .min_by_key(|(_, core)| core.ready.len())
+        .map(|(id, _)| *id)
+        .unwrap_or_else(|| BSP_ID.load(Ordering::SeqCst))
+}
+
+pub fn enqueue(cpu: u32, task: &'static mut Task) {
+    CORES
+        .lock()
+        .entry(cpu)
+        .or_insert_with(|| Core { ready: RawQueue::new() })
+        .ready
+        .push(task);
+}
+
+/// CPU-local equivalent of `enqueue`: pushes `task` onto *this* core's own
+/// ready queue, used by `schedule()` to put the task it's switching away
+/// from back up for another run.
+pub(super) fn requeue(task: &'static mut Task) {
+    if let Some(core) = CORES.lock().get_mut(&current_cpu()) {
+        core.ready.push(task);
+    }
+}
+
+/// Sends an inter-processor interrupt asking `cpu` to re-enter
+/// `schedule()`, used after `balance()` migrates a task onto it.
+pub fn request_reschedule(cpu: u32) {
+    LocalAPICRegisters::default().send_ipi(LocalAPICId(cpu), InterruptVector::Reschedule as u8);
+}
+
+/// CPU-local equivalent of `task::running_task`.
+pub fn running_task() -> Option<&'static Task> {
+    RUNNING
+        .lock()
+        .get(&current_cpu())
+        .map(|task| unsafe { task.as_ref() })
+}
+
+pub(super) fn set_running(task: Option<NonNull<Task>>) {
+    let cpu = current_cpu();
+    let mut running = RUNNING.lock();
+    match task {
+        Some(task) => {
+            running.insert(cpu, task);
+        }
+        None => {
+            running.remove(&cpu);
+        }
+    }
+}
+
+pub(super) fn local_ready_pop() -> Option<&'static mut Task> {
+    CORES.lock().get_mut(&current_cpu())?.ready.pop()
+}
+
+pub(super) fn take_running() -> Option<NonNull<Task>> {
+    RUNNING.lock().remove(&current_cpu())
+}
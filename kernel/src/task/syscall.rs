@@ -0,0 +1,284 @@
+use alloc::collections::BTreeMap;
+use core::arch::asm;
+use core::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    device::driver::keyboard::getch,
+    fs::{self, File},
+    gdt,
+    gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR},
+    print,
+};
+
+use super::{exit, schedule};
+
+/// Syscall numbers passed in `rax`, matching what `kernel_main` already
+/// exercises through `print_input`/the `fs` open/read/write/close calls.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallNumber {
+    Write = 0,
+    Read = 1,
+    Open = 2,
+    Close = 3,
+    Exit = 4,
+    Yield = 5,
+}
+
+impl SyscallNumber {
+    fn from_raw(raw: u64) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Write),
+            1 => Some(Self::Read),
+            2 => Some(Self::Open),
+            3 => Some(Self::Close),
+            4 => Some(Self::Exit),
+            5 => Some(Self::Yield),
+            _ => None,
+        }
+    }
+}
+
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_STAR: u32 = 0xC000_0081;
+const MSR_LSTAR: u32 = 0xC000_0082;
+const MSR_SFMASK: u32 = 0xC000_0084;
+const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const EFER_SCE: u64 = 1 << 0;
+
+/// Upper bound on concurrent CPUs; just sizes `SYSCALL_STACKS` below, one
+/// slot per core handed out by `init_syscall` as each CPU brings itself
+/// up (see `task::smp::ap_long_mode_entry`).
+const MAX_CPUS: usize = 32;
+
+/// `SYSCALL` doesn't switch `rsp`, and the TSS's `RSP0` mechanism the CPU
+/// consults on a ring-3 interrupt is never read by `SYSCALL` either, so
+/// `syscall_entry` needs its own way to find a safe kernel stack before
+/// it can touch memory. `KERNEL_GS_BASE` is pointed at one of these per
+/// CPU; `swapgs` at entry swaps it into `GS_BASE`, making it reachable as
+/// `gs:[0]`/`gs:[8]` with no register left to spare for an address.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SyscallStack {
+    /// Kept in sync with the TSS's `RSP0` by `set_kernel_stack` below,
+    /// called everywhere `task::schedule` switches to a new task.
+    kernel_rsp: u64,
+    /// Scratch slot `syscall_entry` stashes the caller's `rsp` in while
+    /// the handler runs on `kernel_rsp`, restored before `sysretq`.
+    user_rsp: u64,
+}
+
+impl SyscallStack {
+    const fn new() -> Self {
+        Self {
+            kernel_rsp: 0,
+            user_rsp: 0,
+        }
+    }
+}
+
+static mut SYSCALL_STACKS: [SyscallStack; MAX_CPUS] = [SyscallStack::new(); MAX_CPUS];
+static NEXT_SYSCALL_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Fixed fds every task is born with: `sys_write`/`sys_read` special-case
+/// these to go straight to the console instead of through `OPEN_FILES`.
+const STDIN_FD: i64 = 0;
+const STDOUT_FD: i64 = 1;
+
+/// `sys_open`'s `flags`: the only distinction the `fs` layer's `open`
+/// modes make is read vs. write, so that's all a ring-3 task can ask for.
+const O_WRITE: u64 = 1 << 0;
+
+/// Files opened by ring-3 tasks via `sys_open`, keyed by the fd handed
+/// back to the caller. Shared across every task rather than split into
+/// per-task tables, same as every other piece of this kernel's state --
+/// there's no process isolation here, just cooperative tasks.
+static OPEN_FILES: Mutex<BTreeMap<i64, File>> = Mutex::new(BTreeMap::new());
+static NEXT_FD: AtomicI64 = AtomicI64::new(2);
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nomem, nostack),
+    );
+}
+
+/// Enables `SYSCALL`/`SYSRET` and points `LSTAR` at the trap entry point.
+/// `STAR` packs both the ring-0 selectors used on entry and the ring-3
+/// selectors `SYSRET` restores on return, per the AMD64 calling
+/// convention (kernel CS in bits 32-47, user CS base in bits 48-63). Also
+/// hands this CPU its own `SyscallStack` slot and loads it into
+/// `KERNEL_GS_BASE`; every CPU (BSP and each AP) must call this once
+/// before it can run ring-3 tasks.
+pub fn init_syscall() {
+    unsafe {
+        wrmsr(MSR_EFER, rdmsr(MSR_EFER) | EFER_SCE);
+
+        let star = ((KERNEL_CODE_SELECTOR as u64) << 32)
+            | (((USER_CODE_SELECTOR & !0x3) as u64 - 16) << 48);
+        wrmsr(MSR_STAR, star);
+
+        wrmsr(MSR_LSTAR, syscall_entry as u64);
+        // Mask interrupts (IF) on entry so a nested trap can't preempt us
+        // before we've switched onto the task's kernel stack.
+        wrmsr(MSR_SFMASK, 0x200);
+
+        let slot = NEXT_SYSCALL_SLOT.fetch_add(1, Ordering::SeqCst) % MAX_CPUS;
+        let stack = core::ptr::addr_of_mut!(SYSCALL_STACKS[slot]);
+        wrmsr(MSR_KERNEL_GS_BASE, stack as u64);
+    }
+}
+
+/// Mirrors `stack_top` into both the TSS's `RSP0` (consulted on a ring-3
+/// interrupt) and this CPU's `SyscallStack` (consulted by `syscall_entry`
+/// itself), so `task::schedule` has one call that keeps both in sync.
+pub(super) fn set_kernel_stack(stack_top: u64) {
+    gdt::set_kernel_stack(stack_top);
+    unsafe {
+        let stack = rdmsr(MSR_KERNEL_GS_BASE) as *mut SyscallStack;
+        if let Some(stack) = stack.as_mut() {
+            stack.kernel_rsp = stack_top;
+        }
+    }
+}
+
+/// Naked `SYSCALL` entry point. `rcx` holds the return `rip` and `r11`
+/// the caller's `rflags`, both clobbered by the instruction itself, so
+/// they're saved before anything else can touch them. Two things have to
+/// happen before `syscall_handler` (a plain Rust `extern "C"` function)
+/// can be called:
+///
+/// - `swapgs` brings in this CPU's `SyscallStack`, so the caller's `rsp`
+///   (still the ring-3 stack -- `SYSCALL` never switches it) can be
+///   stashed and swapped for `kernel_rsp`. Running the handler on an
+///   untrusted, possibly-unmapped user stack would let a task crash or
+///   corrupt the kernel.
+/// - the SYSCALL convention passes the number in `rax` and args in
+///   `rdi`/`rsi`/`rdx` (a 4th arg would go in `r10`, since `rcx` is
+///   reserved for the return address, but nothing here takes one); the
+///   SysV callee `syscall_handler` expects them one register over, in
+///   `rdi`/`rsi`/`rdx`/`rcx`. The four `mov`s below shift them into place
+///   back-to-front so no source is clobbered before it's read.
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[8], rsp",
+        "mov rsp, gs:[0]",
+        "push rcx",
+        "push r11",
+        "mov rcx, rdx",
+        "mov rdx, rsi",
+        "mov rsi, rdi",
+        "mov rdi, rax",
+        "call {handler}",
+        "pop r11",
+        "pop rcx",
+        "mov rsp, gs:[8]",
+        "swapgs",
+        "sysretq",
+        handler = sym syscall_handler,
+    );
+}
+
+/// Dispatches on the syscall number (passed in `rdi` by `syscall_entry`,
+/// having arrived in `rax` per the SYSCALL convention), with arguments in
+/// `rsi`/`rdx`/`rcx`. Returns the result in `rax`. `Write`/`Read` take the
+/// fd as their first argument, falling back to the console for
+/// `STDIN_FD`/`STDOUT_FD` and the `fs`-backed `OPEN_FILES` table for
+/// anything `sys_open` handed out.
+pub extern "C" fn syscall_handler(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    match SyscallNumber::from_raw(number) {
+        Some(SyscallNumber::Write) => sys_write(arg0, arg1, arg2),
+        Some(SyscallNumber::Read) => sys_read(arg0, arg1, arg2),
+        Some(SyscallNumber::Open) => sys_open(arg0, arg1, arg2),
+        Some(SyscallNumber::Close) => sys_close(arg0),
+        Some(SyscallNumber::Yield) => {
+            schedule();
+            0
+        }
+        Some(SyscallNumber::Exit) => exit(),
+        None => -1,
+    }
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64) -> i64 {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+
+    if fd as i64 == STDOUT_FD {
+        return match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                print!("{text}");
+                len as i64
+            }
+            Err(_) => -1,
+        };
+    }
+
+    match OPEN_FILES.lock().get_mut(&(fd as i64)) {
+        Some(file) => file.write(bytes).map_or(-1, |written| written as i64),
+        None => -1,
+    }
+}
+
+fn sys_read(fd: u64, ptr: u64, len: u64) -> i64 {
+    let buffer = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) };
+
+    if fd as i64 == STDIN_FD {
+        let mut read = 0;
+        while read < buffer.len() {
+            buffer[read] = getch();
+            read += 1;
+            if buffer[read - 1] == b'\n' {
+                break;
+            }
+        }
+        return read as i64;
+    }
+
+    match OPEN_FILES.lock().get_mut(&(fd as i64)) {
+        Some(file) => file.read(buffer).map_or(-1, |read| read as i64),
+        None => -1,
+    }
+}
+
+/// Resolves `path` against the first device `fs::dev_list()` reports,
+/// opens it in the mode `flags` asks for, and files the result away in
+/// `OPEN_FILES` under a freshly allocated fd.
+fn sys_open(path_ptr: u64, path_len: u64, flags: u64) -> i64 {
+    let bytes = unsafe { core::slice::from_raw_parts(path_ptr as *const u8, path_len as usize) };
+    let Ok(path) = core::str::from_utf8(bytes) else {
+        return -1;
+    };
+    let Some(dev_name) = fs::dev_list().into_iter().next() else {
+        return -1;
+    };
+    let mode: &[u8] = if flags & O_WRITE != 0 { b"w" } else { b"r" };
+
+    let Ok(file) = fs::open(&dev_name, 0, path, mode) else {
+        return -1;
+    };
+
+    let fd = NEXT_FD.fetch_add(1, Ordering::SeqCst);
+    OPEN_FILES.lock().insert(fd, file);
+    fd
+}
+
+fn sys_close(fd: u64) -> i64 {
+    match OPEN_FILES.lock().remove(&(fd as i64)) {
+        Some(_) => 0,
+        None => -1,
+    }
+}
@@ -0,0 +1,275 @@
+mod opcode;
+
+use alloc::vec::Vec;
+
+use crate::{
+    allocator::{free, malloc},
+    task::syscall_handler,
+};
+
+use opcode::Opcode;
+
+pub const REGISTER_COUNT: usize = 256;
+
+/// Upper bound on instructions executed per `schedule()` slice: a VM task
+/// never gets native preemption, so this is what stands in for it.
+pub const MAX_STEPS_PER_SLICE: usize = 10_000;
+
+/// 256 general registers, each reinterpretable as a 64-bit integer or an
+/// `f64` without a union — the bit pattern is identical either way.
+#[derive(Clone, Copy)]
+struct Registers([u64; REGISTER_COUNT]);
+
+impl Registers {
+    const fn new() -> Self {
+        Self([0; REGISTER_COUNT])
+    }
+
+    fn int(&self, reg: u8) -> i64 {
+        self.0[reg as usize] as i64
+    }
+
+    fn set_int(&mut self, reg: u8, value: i64) {
+        self.0[reg as usize] = value as u64;
+    }
+
+    fn float(&self, reg: u8) -> f64 {
+        f64::from_bits(self.0[reg as usize])
+    }
+
+    fn set_float(&mut self, reg: u8, value: f64) {
+        self.0[reg as usize] = value.to_bits();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmFault {
+    InvalidOpcode(u8),
+    OutOfBounds,
+    CallStackUnderflow,
+    CallStackOverflow,
+}
+
+/// A sandboxed register-machine instance: a read-only code segment plus a
+/// data/stack segment carved out of `malloc`, which is all the program is
+/// able to address. Owned by exactly one `Task`.
+pub struct Vm {
+    registers: Registers,
+    pc: usize,
+    code: &'static [u8],
+    data: *mut u8,
+    data_len: usize,
+    call_stack: Vec<usize>,
+    halted: bool,
+    fault: Option<VmFault>,
+}
+
+const MAX_CALL_DEPTH: usize = 256;
+
+impl Vm {
+    pub fn new(code: &'static [u8], data_len: usize) -> Self {
+        Self {
+            registers: Registers::new(),
+            pc: 0,
+            code,
+            data: malloc(data_len, 8),
+            data_len,
+            call_stack: Vec::new(),
+            halted: false,
+            fault: None,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted || self.fault.is_some()
+    }
+
+    pub fn fault(&self) -> Option<VmFault> {
+        self.fault
+    }
+
+    /// Frees the data/stack segment. Called from `Task`'s teardown path;
+    /// the code segment is borrowed, not owned, so there's nothing to
+    /// release there.
+    pub fn release(&mut self) {
+        if !self.data.is_null() {
+            free(self.data);
+            self.data = core::ptr::null_mut();
+        }
+    }
+
+    /// Runs up to `MAX_STEPS_PER_SLICE` instructions, stopping early on
+    /// `halt`, a decode/bounds fault, or end of the code segment.
+    pub fn run_slice(&mut self) {
+        for _ in 0..MAX_STEPS_PER_SLICE {
+            if self.is_halted() || self.pc >= self.code.len() {
+                return;
+            }
+            self.step();
+        }
+    }
+
+    /// `None` means the code segment ran out mid-instruction -- a
+    /// truncated or malicious program, not a bug -- so every caller
+    /// propagates it with `?` rather than indexing `self.code` directly.
+    fn fetch_u8(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.pc)?;
+        self.pc += 1;
+        Some(byte)
+    }
+
+    fn fetch_i32(&mut self) -> Option<i32> {
+        let bytes = [
+            self.fetch_u8()?,
+            self.fetch_u8()?,
+            self.fetch_u8()?,
+            self.fetch_u8()?,
+        ];
+        Some(i32::from_le_bytes(bytes))
+    }
+
+    /// Validates a decoded jump/call target before committing it to
+    /// `self.pc`; an out-of-range target would otherwise leave `self.pc`
+    /// past the end of `self.code`, which `run_slice` would take for a
+    /// clean end of program instead of a fault.
+    /// `target == self.code.len()` is allowed -- `run_slice` treats it as
+    /// a clean end of program, not a fault.
+    fn jump_to(&mut self, target: i32) -> Option<()> {
+        if target < 0 || target as usize > self.code.len() {
+            self.fault = Some(VmFault::OutOfBounds);
+            return None;
+        }
+        self.pc = target as usize;
+        Some(())
+    }
+
+    fn data_slice(&self, offset: i32, len: usize) -> Option<&[u8]> {
+        let offset = offset as isize;
+        if offset < 0 || offset as usize + len > self.data_len {
+            return None;
+        }
+        Some(unsafe { core::slice::from_raw_parts(self.data.offset(offset), len) })
+    }
+
+    fn data_slice_mut(&mut self, offset: i32, len: usize) -> Option<&mut [u8]> {
+        let offset = offset as isize;
+        if offset < 0 || offset as usize + len > self.data_len {
+            return None;
+        }
+        Some(unsafe { core::slice::from_raw_parts_mut(self.data.offset(offset), len) })
+    }
+
+    /// Runs one instruction. Any operand or jump target that runs off the
+    /// end of `self.code` is reported as `VmFault::OutOfBounds` by the
+    /// `step` wrapper below rather than panicking -- a VM program is
+    /// untrusted input, not a trusted caller.
+    fn step(&mut self) {
+        if self.try_step().is_none() {
+            self.fault.get_or_insert(VmFault::OutOfBounds);
+        }
+    }
+
+    fn try_step(&mut self) -> Option<()> {
+        let opcode_byte = self.fetch_u8()?;
+        let opcode = match Opcode::decode(opcode_byte) {
+            Some(opcode) => opcode,
+            None => {
+                self.fault = Some(VmFault::InvalidOpcode(opcode_byte));
+                return Some(());
+            }
+        };
+
+        match opcode {
+            Opcode::Nop => {}
+            Opcode::AddI | Opcode::SubI | Opcode::MulI | Opcode::DivI => {
+                let (dst, a, b) = (self.fetch_u8()?, self.fetch_u8()?, self.fetch_u8()?);
+                let (lhs, rhs) = (self.registers.int(a), self.registers.int(b));
+                let result = match opcode {
+                    Opcode::AddI => lhs.wrapping_add(rhs),
+                    Opcode::SubI => lhs.wrapping_sub(rhs),
+                    Opcode::MulI => lhs.wrapping_mul(rhs),
+                    Opcode::DivI if rhs != 0 => lhs.wrapping_div(rhs),
+                    _ => 0,
+                };
+                self.registers.set_int(dst, result);
+            }
+            Opcode::AddF | Opcode::SubF | Opcode::MulF | Opcode::DivF => {
+                let (dst, a, b) = (self.fetch_u8()?, self.fetch_u8()?, self.fetch_u8()?);
+                let (lhs, rhs) = (self.registers.float(a), self.registers.float(b));
+                let result = match opcode {
+                    Opcode::AddF => lhs + rhs,
+                    Opcode::SubF => lhs - rhs,
+                    Opcode::MulF => lhs * rhs,
+                    Opcode::DivF => lhs / rhs,
+                    _ => 0.0,
+                };
+                self.registers.set_float(dst, result);
+            }
+            Opcode::Load => {
+                let (dst, base) = (self.fetch_u8()?, self.fetch_u8()?);
+                let offset = self.fetch_i32()? + self.registers.int(base) as i32;
+                match self.data_slice(offset, 8) {
+                    Some(bytes) => {
+                        self.registers
+                            .set_int(dst, i64::from_le_bytes(bytes.try_into().unwrap()))
+                    }
+                    None => self.fault = Some(VmFault::OutOfBounds),
+                }
+            }
+            Opcode::Store => {
+                let (src, base) = (self.fetch_u8()?, self.fetch_u8()?);
+                let offset = self.fetch_i32()? + self.registers.int(base) as i32;
+                let value = self.registers.int(src).to_le_bytes();
+                match self.data_slice_mut(offset, 8) {
+                    Some(bytes) => bytes.copy_from_slice(&value),
+                    None => self.fault = Some(VmFault::OutOfBounds),
+                }
+            }
+            Opcode::Jmp => {
+                let target = self.fetch_i32()?;
+                self.jump_to(target)?;
+            }
+            Opcode::Jz | Opcode::Jnz => {
+                let reg = self.fetch_u8()?;
+                let target = self.fetch_i32()?;
+                let take = self.registers.int(reg) == 0;
+                if take == (opcode == Opcode::Jz) {
+                    self.jump_to(target)?;
+                }
+            }
+            Opcode::Jlt => {
+                let (a, b) = (self.fetch_u8()?, self.fetch_u8()?);
+                let target = self.fetch_i32()?;
+                if self.registers.int(a) < self.registers.int(b) {
+                    self.jump_to(target)?;
+                }
+            }
+            Opcode::Call => {
+                let target = self.fetch_i32()?;
+                if self.call_stack.len() >= MAX_CALL_DEPTH {
+                    self.fault = Some(VmFault::CallStackOverflow);
+                    return Some(());
+                }
+                let return_pc = self.pc;
+                self.jump_to(target)?;
+                self.call_stack.push(return_pc);
+            }
+            Opcode::Ret => match self.call_stack.pop() {
+                Some(return_pc) => self.pc = return_pc,
+                None => self.fault = Some(VmFault::CallStackUnderflow),
+            },
+            Opcode::Ecall => {
+                let number = self.registers.int(0) as u64;
+                let args = (
+                    self.registers.int(1) as u64,
+                    self.registers.int(2) as u64,
+                    self.registers.int(3) as u64,
+                );
+                let result = syscall_handler(number, args.0, args.1, args.2);
+                self.registers.set_int(0, result);
+            }
+            Opcode::Halt => self.halted = true,
+        }
+        Some(())
+    }
+}
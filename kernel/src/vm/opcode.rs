@@ -0,0 +1,54 @@
+/// Instruction set for the sandboxed register VM. Operands are fixed per
+/// opcode (register indices are one byte, immediates/targets are a
+/// little-endian `i32`): arithmetic takes 3 bytes, load/store and the
+/// two-register compare-jump 6, `jz`/`jnz` 5, `jmp`/`call` 4, and
+/// `nop`/`ret`/`ecall`/`halt` none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Nop,
+    AddI,
+    SubI,
+    MulI,
+    DivI,
+    AddF,
+    SubF,
+    MulF,
+    DivF,
+    Load,
+    Store,
+    Jmp,
+    Jz,
+    Jnz,
+    Jlt,
+    Call,
+    Ret,
+    Ecall,
+    Halt,
+}
+
+impl Opcode {
+    pub fn decode(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0x00 => Self::Nop,
+            0x01 => Self::AddI,
+            0x02 => Self::SubI,
+            0x03 => Self::MulI,
+            0x04 => Self::DivI,
+            0x05 => Self::AddF,
+            0x06 => Self::SubF,
+            0x07 => Self::MulF,
+            0x08 => Self::DivF,
+            0x09 => Self::Load,
+            0x0A => Self::Store,
+            0x0B => Self::Jmp,
+            0x0C => Self::Jz,
+            0x0D => Self::Jnz,
+            0x0E => Self::Jlt,
+            0x0F => Self::Call,
+            0x10 => Self::Ret,
+            0x11 => Self::Ecall,
+            0xFF => Self::Halt,
+            _ => return None,
+        })
+    }
+}